@@ -6,67 +6,546 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::time;
+use std::time::Duration;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Waker};
 
-use domain::Command;
+#[cfg(feature = "async")]
+use futures::sink::Sink;
+#[cfg(feature = "async")]
+use futures::stream::Stream;
+
+use domain::{Command, Var, Value};
 use domain::notify::*;
-use util::Consume;
+use util::{Consume, ConsumeError, OneshotReceiver, OneshotSender, Recv, Selected,
+           TryConsume, oneshot, select3};
+
+/// A handle to the per-variable schedule held by the worker.
+///
+/// Handed to `Handle::command()` and `Handle::on_timeout()` so the handler
+/// can arm, rearm or cancel timeouts without the worker having to know
+/// anything about what they mean.
+pub struct Scheduler<'a> {
+    wheel: &'a mut TimingWheel,
+}
 
-const POLLING_DELAY_MS: u64 = 20;
+impl<'a> Scheduler<'a> {
+    /// Schedule `token` to fire after `interval`. When `periodic` is true the
+    /// token is automatically rearmed for the same interval every time it
+    /// fires.
+    pub fn schedule(&mut self, token: Token, interval: Duration, periodic: bool) {
+        self.wheel.schedule(token, interval, periodic);
+    }
+
+    /// Cancel a previously scheduled token. Tolerates a token that already
+    /// fired or was never scheduled.
+    pub fn cancel(&mut self, token: Token) {
+        self.wheel.cancel(token);
+    }
+}
 
 pub trait Handle {
     fn new() -> Self;
-    fn command(&mut self, cmd: Command);
-    fn poll(&mut self);
+    fn command(&mut self, cmd: Command, scheduler: &mut Scheduler, watchers: &mut Watchers);
+    fn on_timeout(&mut self, token: Token, scheduler: &mut Scheduler, watchers: &mut Watchers);
+
+    /// Answer a `Read` request for `var`. Unlike `command()`, the result is
+    /// not fire-and-forget: the worker sends it back over the requester's
+    /// oneshot reply channel exactly once.
+    fn read(&mut self, var: Var) -> Value;
+
+    /// Answer a `List` request with the set of variables this handler
+    /// currently knows about.
+    fn list(&mut self) -> Vec<Var>;
+}
+
+/// A watch cell shared between a single `WatchSender` and any number of
+/// `WatchReceiver`s cloned from it: the sender overwrites the stored value
+/// and bumps a generation counter, and each receiver compares its own
+/// last-seen generation to decide whether the value changed.
+struct WatchCell<T> {
+    value: T,
+    generation: usize,
+}
+
+pub struct WatchSender<T> {
+    cell: Arc<RwLock<WatchCell<T>>>,
+}
+
+impl<T: Clone> WatchSender<T> {
+    /// Overwrite the stored value and bump its generation. No receiver is
+    /// notified or woken: a receiver sees the change the next time it
+    /// calls `has_changed()` or `get()`.
+    pub fn send(&self, value: T) {
+        let mut cell = self.cell.write().unwrap();
+        cell.value = value;
+        cell.generation += 1;
+    }
+
+    /// A new receiver over the same cell, starting at generation 0 so it
+    /// immediately observes the current latest value.
+    pub fn subscribe(&self) -> WatchReceiver<T> {
+        WatchReceiver {
+            cell: self.cell.clone(),
+            seen: 0,
+        }
+    }
+}
+
+impl<T> Clone for WatchSender<T> {
+    fn clone(&self) -> Self {
+        WatchSender { cell: self.cell.clone() }
+    }
+}
+
+pub struct WatchReceiver<T> {
+    cell: Arc<RwLock<WatchCell<T>>>,
+    seen: usize,
+}
+
+impl<T: Clone> WatchReceiver<T> {
+    /// The current latest value together with its generation, regardless
+    /// of whether it is new since the last read.
+    pub fn get(&mut self) -> (T, usize) {
+        let cell = self.cell.read().unwrap();
+        self.seen = cell.generation;
+        (cell.value.clone(), cell.generation)
+    }
+
+    /// Whether the stored value has a newer generation than the last one
+    /// observed through `get()`.
+    pub fn has_changed(&self) -> bool {
+        self.cell.read().unwrap().generation != self.seen
+    }
+}
+
+impl<T> Clone for WatchReceiver<T> {
+    fn clone(&self) -> Self {
+        WatchReceiver {
+            cell: self.cell.clone(),
+            seen: 0,
+        }
+    }
+}
+
+fn watch_channel<T>(initial: T) -> (WatchSender<T>, WatchReceiver<T>) {
+    let cell = Arc::new(RwLock::new(WatchCell { value: initial, generation: 0 }));
+    let sender = WatchSender { cell: cell.clone() };
+    let receiver = WatchReceiver { cell: cell, seen: 0 };
+    (sender, receiver)
+}
+
+/// Registry of watch channels keyed by `Var`, owned by the `Worker` and
+/// handed to the handler so it can publish outgoing variable changes
+/// without routing them through the command envelope queue.
+pub struct Watchers {
+    channels: HashMap<Var, WatchSender<Value>>,
+}
+
+impl Watchers {
+    fn new() -> Watchers {
+        Watchers { channels: HashMap::new() }
+    }
+
+    /// Publish a new value for `var`, creating its watch channel on first
+    /// use.
+    pub fn publish(&mut self, var: Var, value: Value) {
+        match self.channels.entry(var) {
+            Entry::Occupied(e) => e.get().send(value),
+            Entry::Vacant(e) => { e.insert(watch_channel(value).0); },
+        }
+    }
+
+    /// Subscribe to `var`, creating its watch channel (seeded with
+    /// `initial`) on first use.
+    pub fn watch(&mut self, var: Var, initial: Value) -> WatchReceiver<Value> {
+        match self.channels.entry(var) {
+            Entry::Occupied(e) => e.get().subscribe(),
+            Entry::Vacant(e) => {
+                let (tx, rx) = watch_channel(initial);
+                e.insert(tx);
+                rx
+            },
+        }
+    }
+}
+
+/// Opaque identifier of a timeout scheduled in the worker's timing wheel.
+pub type Token = u64;
+
+const WHEEL_SLOTS: usize = 512;
+const TICK_MS: u64 = 10;
+
+struct TimerEntry {
+    token: Token,
+    rotations: u64,
+    period: Option<u64>,
+}
+
+/// A hashed timing wheel of `WHEEL_SLOTS` slots, each `TICK_MS` apart, used
+/// to fire many independently-scheduled timeouts without polling every
+/// variable on a single fixed cadence.
+struct TimingWheel {
+    slots: Vec<Vec<TimerEntry>>,
+    current_tick: u64,
+    tick: Duration,
+}
+
+impl TimingWheel {
+    fn new(tick: Duration) -> TimingWheel {
+        TimingWheel {
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            current_tick: 0,
+            tick: tick,
+        }
+    }
+
+    fn ticks_for(&self, interval: Duration) -> u64 {
+        let tick_ms = duration_ms(self.tick).max(1);
+        let interval_ms = duration_ms(interval);
+        interval_ms.div_ceil(tick_ms).max(1)
+    }
+
+    fn schedule(&mut self, token: Token, interval: Duration, periodic: bool) {
+        let ticks = self.ticks_for(interval);
+        self.insert(token, ticks, if periodic { Some(ticks) } else { None });
+    }
+
+    fn insert(&mut self, token: Token, ticks: u64, period: Option<u64>) {
+        let deadline = self.current_tick + ticks;
+        let slot = (deadline % WHEEL_SLOTS as u64) as usize;
+        // Slot `deadline % WHEEL_SLOTS` next comes round within the coming
+        // `WHEEL_SLOTS` ticks, so only the ticks beyond those take extra
+        // rotations (`ticks` is always at least 1).
+        let rotations = (ticks - 1) / WHEEL_SLOTS as u64;
+        self.slots[slot].push(TimerEntry {
+            token: token,
+            rotations: rotations,
+            period: period,
+        });
+    }
+
+    fn cancel(&mut self, token: Token) {
+        for slot in &mut self.slots {
+            slot.retain(|e| e.token != token);
+        }
+    }
+
+    /// Advance the wheel by a single tick, firing (and, if periodic,
+    /// rescheduling) every entry in the current slot whose rotation count
+    /// has reached zero.
+    fn advance(&mut self) -> Vec<Token> {
+        self.current_tick += 1;
+        let slot = (self.current_tick % WHEEL_SLOTS as u64) as usize;
+        let entries = mem::take(&mut self.slots[slot]);
+        let mut fired = Vec::new();
+        let mut reinsert = Vec::new();
+        for mut entry in entries {
+            if entry.rotations == 0 {
+                fired.push(entry.token);
+                if let Some(period) = entry.period {
+                    reinsert.push((entry.token, period));
+                }
+            } else {
+                entry.rotations -= 1;
+                self.slots[slot].push(entry);
+            }
+        }
+        for (token, period) in reinsert {
+            self.insert(token, period, Some(period));
+        }
+        fired
+    }
+
+}
+
+fn duration_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_millis() as u64
+}
+
+/// Configuration a `Worker` is spawned with: how often its timing wheel
+/// ticks, and how many in-flight `Cmd` envelopes its queue holds before
+/// applying backpressure.
+pub struct WorkerConfig {
+    pub tick: Duration,
+    pub capacity: Option<usize>,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> WorkerConfig {
+        WorkerConfig {
+            tick: Duration::from_millis(TICK_MS),
+            capacity: None,
+        }
+    }
+}
+
+/// Tracks in-flight `Cmd` envelopes for a bounded worker so a blocked
+/// producer can wait for a free slot on a condvar instead of spinning:
+/// `acquire()` parks until `release()` (called as the worker drains an
+/// envelope) wakes it back up.
+struct Capacity {
+    outstanding: Mutex<usize>,
+    limit: usize,
+    freed: Condvar,
+    closed: AtomicBool,
+    /// The async counterpart to `freed`: a task parked in `poll_acquire()`
+    /// stores its waker here so `release()`/`close()` can wake it, the
+    /// same way `freed.notify_*` wakes a thread blocked in `acquire()`.
+    #[cfg(feature = "async")]
+    async_waker: Mutex<Option<Waker>>,
+}
+
+impl Capacity {
+    fn new(limit: usize) -> Capacity {
+        Capacity {
+            outstanding: Mutex::new(0),
+            limit: limit,
+            freed: Condvar::new(),
+            closed: AtomicBool::new(false),
+            #[cfg(feature = "async")]
+            async_waker: Mutex::new(None),
+        }
+    }
+
+    /// Block until a slot is free, then occupy it. Fails without blocking
+    /// further once `close()` has been called, so a producer parked here
+    /// when the worker shuts down gets woken and told instead of parked
+    /// forever waiting for a slot that will never be drained again.
+    fn acquire(&self) -> Result<(), NotifyError> {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        while *outstanding >= self.limit {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(NotifyError);
+            }
+            outstanding = self.freed.wait(outstanding).unwrap();
+        }
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(NotifyError);
+        }
+        *outstanding += 1;
+        Ok(())
+    }
+
+    /// Occupy a slot without blocking, failing if none are free.
+    fn try_acquire(&self) -> bool {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        if *outstanding >= self.limit || self.closed.load(Ordering::SeqCst) {
+            false
+        } else {
+            *outstanding += 1;
+            true
+        }
+    }
+
+    /// Occupy a slot without blocking, parking the given task's waker to
+    /// be woken by `release()`/`close()` if none are free yet. Mirrors
+    /// `acquire()` for an async producer polling `AsyncCommands::poll_ready`.
+    #[cfg(feature = "async")]
+    fn poll_acquire(&self, cx: &mut Context) -> Poll<Result<(), NotifyError>> {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(NotifyError));
+        }
+        if *outstanding < self.limit {
+            *outstanding += 1;
+            Poll::Ready(Ok(()))
+        } else {
+            *self.async_waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    /// Free a slot and wake a producer parked in `acquire()`/`poll_acquire()`,
+    /// if any.
+    fn release(&self) {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        *outstanding = outstanding.saturating_sub(1);
+        self.freed.notify_one();
+        #[cfg(feature = "async")]
+        {
+            if let Some(waker) = self.async_waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Wake every producer parked in `acquire()`/`poll_acquire()` and fail
+    /// them instead of leaving them waiting on a slot that shutdown means
+    /// will never free up. Called once the worker has decided to stop.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.freed.notify_all();
+        #[cfg(feature = "async")]
+        {
+            if let Some(waker) = self.async_waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Recv for NotifyReceiver<T> {
+    type Item = T;
+    fn poll(&mut self, timeout: Duration) -> Option<T> {
+        match self.recv_timeout(timeout) {
+            Ok(Some(item)) => Some(item),
+            _ => None,
+        }
+    }
 }
 
 pub struct Worker {
-    tx: NotifySender<Envelope>,
-    rx: NotifyReceiver<Envelope>,
+    command_tx: NotifySender<Envelope>,
+    command_rx: NotifyReceiver<Envelope>,
+    shutdown_tx: NotifySender<()>,
+    shutdown_rx: NotifyReceiver<()>,
+    capacity: Option<Arc<Capacity>>,
+    tick: Duration,
     run: bool,
 }
 
 impl Worker {
     pub fn new() -> Worker {
-        let (tx, rx) = notification();
+        Worker::with_config(WorkerConfig::default())
+    }
+
+    /// A worker whose command queue never holds more than `capacity`
+    /// in-flight `Cmd` envelopes: producers using `Consumer::consume()`
+    /// block until a slot frees up, and `Consumer::try_consume()` fails
+    /// fast with `ConsumeError::Full` instead.
+    pub fn bounded(capacity: usize) -> Worker {
+        Worker::with_config(WorkerConfig { capacity: Some(capacity), ..WorkerConfig::default() })
+    }
+
+    pub fn with_config(config: WorkerConfig) -> Worker {
+        let (command_tx, command_rx) = notification();
+        let (shutdown_tx, shutdown_rx) = notification();
         Worker {
-            tx: tx,
-            rx: rx,
+            command_tx: command_tx,
+            command_rx: command_rx,
+            shutdown_tx: shutdown_tx,
+            shutdown_rx: shutdown_rx,
+            capacity: config.capacity.map(|limit| Arc::new(Capacity::new(limit))),
+            tick: config.tick,
             run: true,
         }
     }
 
     pub fn sender(&self) -> NotifySender<Envelope> {
-        self.tx.clone()
+        self.command_tx.clone()
+    }
+
+    /// A dedicated, high-priority sender: a `Shutdown` sent through this
+    /// channel preempts any backlog of pending commands, rather than
+    /// waiting behind them in the command queue.
+    pub fn shutdown_sender(&self) -> NotifySender<()> {
+        self.shutdown_tx.clone()
+    }
+
+    pub fn consumer(&self) -> Consumer {
+        Consumer {
+            sender: self.command_tx.clone(),
+            capacity: self.capacity.clone(),
+        }
     }
 
     pub fn run<H: Handle>(&mut self, handler: &mut H) {
         self.run = true;
-        let timeout = time::Duration::from_millis(POLLING_DELAY_MS);
+        let mut wheel = TimingWheel::new(self.tick);
+        let mut watchers = Watchers::new();
+
+        let (ticker_tx, mut ticker_rx) = notification::<()>();
+        let keep_ticking = Arc::new(AtomicBool::new(true));
+        let ticker_flag = keep_ticking.clone();
+        let tick = self.tick;
+        let ticker = thread::spawn(move || {
+            while ticker_flag.load(Ordering::SeqCst) {
+                thread::sleep(tick);
+                if ticker_tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+
         while self.run {
-            match self.rx.recv_timeout(timeout) {
-                Ok(Some(Envelope::Shutdown)) => self.shutdown(),
-                Ok(Some(Envelope::Cmd(cmd))) => handler.command(cmd),
-                Ok(None) => handler.poll(),
-                _ => {},
+            let selected = select3(
+                &mut self.shutdown_rx, &mut self.command_rx, &mut ticker_rx, self.tick);
+            match selected {
+                Some(Selected::First(())) => self.shutdown(),
+                Some(Selected::Second(Envelope::Cmd(cmd))) => {
+                    if let Some(ref capacity) = self.capacity {
+                        capacity.release();
+                    }
+                    let mut scheduler = Scheduler { wheel: &mut wheel };
+                    handler.command(cmd, &mut scheduler, &mut watchers);
+                },
+                Some(Selected::Second(Envelope::Read(var, reply))) => {
+                    reply.send(handler.read(var));
+                },
+                Some(Selected::Second(Envelope::List(reply))) => {
+                    reply.send(handler.list());
+                },
+                Some(Selected::Second(Envelope::Watch(var, reply))) => {
+                    let initial = handler.read(var.clone());
+                    reply.send(watchers.watch(var, initial));
+                },
+                Some(Selected::Third(())) => {
+                    for token in wheel.advance() {
+                        let mut scheduler = Scheduler { wheel: &mut wheel };
+                        handler.on_timeout(token, &mut scheduler, &mut watchers);
+                        // A tick that fires a flood of timeouts shouldn't
+                        // delay shutdown behind all of them: peek the
+                        // dedicated shutdown channel between firings
+                        // rather than only at the top of the outer loop.
+                        if self.shutdown_rx.poll(Duration::from_millis(0)).is_some() {
+                            self.shutdown();
+                            break;
+                        }
+                    }
+                },
+                None => {},
             }
         }
+
+        keep_ticking.store(false, Ordering::SeqCst);
+        let _ = ticker.join();
     }
 
     pub fn shutdown(&mut self) {
         self.run = false;
+        if let Some(ref capacity) = self.capacity {
+            capacity.close();
+        }
     }
 }
 
-#[derive(Debug)]
 pub enum Envelope {
     Cmd(Command),
-    Shutdown
+    /// A `Read` request: the worker answers it by calling `Handle::read()`
+    /// and sending the result over the carried oneshot reply channel.
+    Read(Var, OneshotSender<Value>),
+    /// A `List` request, answered the same way via `Handle::list()`.
+    List(OneshotSender<Vec<Var>>),
+    /// A request to subscribe to `var`'s watch channel. The worker seeds
+    /// it with `Handle::read()` on first subscription and sends back a
+    /// `WatchReceiver` the caller can poll on its own, with no further
+    /// trips through this queue.
+    Watch(Var, OneshotSender<WatchReceiver<Value>>),
 }
 
 pub struct WorkerStub {
     sender: NotifySender<Envelope>,
+    shutdown_sender: NotifySender<()>,
+    capacity: Option<Arc<Capacity>>,
     child: thread::JoinHandle<()>,
 }
 
@@ -74,11 +553,12 @@ impl WorkerStub {
     pub fn consumer(&self) -> Consumer {
         Consumer {
             sender: self.sender.clone(),
+            capacity: self.capacity.clone(),
         }
     }
 
     pub fn shutdown(self) {
-        if let Err(e) = self.sender.send(Envelope::Shutdown) {
+        if let Err(e) = self.shutdown_sender.send(()) {
             error!("unexpected error while shutting down domain worker: {:?}", e);
         }
         if let Err(e) = self.child.join() {
@@ -90,19 +570,230 @@ impl WorkerStub {
 #[derive(Clone)]
 pub struct Consumer {
     sender: NotifySender<Envelope>,
+    capacity: Option<Arc<Capacity>>,
 }
 
 impl Consume for Consumer {
     type Item = Command;
     type Error = NotifyError;
     fn consume(&mut self, cmd: Command) -> Result<(), NotifyError> {
+        if let Some(ref capacity) = self.capacity {
+            capacity.acquire()?;
+        }
         self.sender.send(Envelope::Cmd(cmd))
     }
 }
 
+impl TryConsume for Consumer {
+    fn try_consume(&mut self, cmd: Command) -> Result<(), ConsumeError<NotifyError>> {
+        if let Some(ref capacity) = self.capacity {
+            if !capacity.try_acquire() {
+                return Err(ConsumeError::Full);
+            }
+        }
+        self.sender.send(Envelope::Cmd(cmd)).map_err(ConsumeError::Disconnected)
+    }
+}
+
+impl Consumer {
+    /// Request the current value of `var` and return a receiver for the
+    /// single reply, so callers can issue a read and await its response
+    /// instead of only pushing fire-and-forget commands.
+    pub fn request(&mut self, var: Var) -> Result<OneshotReceiver<Value>, NotifyError> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.sender.send(Envelope::Read(var, reply_tx))?;
+        Ok(reply_rx)
+    }
+
+    /// Request the list of variables the handler currently knows about.
+    pub fn list(&mut self) -> Result<OneshotReceiver<Vec<Var>>, NotifyError> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.sender.send(Envelope::List(reply_tx))?;
+        Ok(reply_rx)
+    }
+
+    /// Subscribe to `var`'s watch channel, giving a client that polls
+    /// slower than the simulation a "current value" read of bounded
+    /// memory instead of an ever-growing queue of intermediate changes.
+    /// The returned `WatchReceiver` is polled directly by the caller and
+    /// does not go back through the worker's command queue.
+    pub fn watch(&mut self, var: Var) -> Result<OneshotReceiver<WatchReceiver<Value>>, NotifyError> {
+        let (reply_tx, reply_rx) = oneshot();
+        self.sender.send(Envelope::Watch(var, reply_tx))?;
+        Ok(reply_rx)
+    }
+}
+
+/// The slot a `CommandStream` parks its task waker in while idle, so the
+/// `AsyncCommands` sink paired with it can wake the task back up the
+/// moment a new command is enqueued, instead of every producer needing
+/// a dedicated OS thread to poll for one.
+#[cfg(feature = "async")]
+type WakerSlot = Arc<Mutex<Option<Waker>>>;
+
+/// The async counterpart to `Consumer`: a non-blocking `Sink<Command>`
+/// for producers running on an async runtime, paired with the
+/// `CommandStream` below. Built with `into_async()`, as an alternative
+/// to the blocking `Worker::run()` loop, not alongside it.
+#[cfg(feature = "async")]
+pub struct AsyncCommands {
+    sender: NotifySender<Envelope>,
+    waker: WakerSlot,
+    capacity: Option<Arc<Capacity>>,
+}
+
+#[cfg(feature = "async")]
+impl Sink<Command> for AsyncCommands {
+    type Error = NotifyError;
+
+    /// Mirrors `Consumer::consume()`'s blocking `Capacity::acquire()`: a
+    /// bounded worker's limit applies here too, parking the task instead
+    /// of the thread while the queue is full.
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), NotifyError>> {
+        let this = Pin::into_inner(self);
+        match this.capacity {
+            Some(ref capacity) => capacity.poll_acquire(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Command) -> Result<(), NotifyError> {
+        let this = Pin::into_inner(self);
+        this.sender.send(Envelope::Cmd(item))?;
+        if let Some(waker) = this.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), NotifyError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), NotifyError>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The async counterpart to `Worker::run()`'s command handling: a
+/// `Stream<Item = Command>` over the worker's receive side, for
+/// embedders that want to pull commands from their own async executor
+/// instead of dedicating an OS thread to `Worker::run()`. `Read` and
+/// `List` requests are not meaningful here and are dropped, which
+/// reports `RequestError::Canceled` to whoever issued them; this stream
+/// is for the fire-and-forget `Command` side only, matching the scope
+/// of `Consume`. Ends with `None` once every `AsyncCommands` sink has
+/// dropped or the worker has been told to shut down.
+#[cfg(feature = "async")]
+pub struct CommandStream {
+    command_rx: NotifyReceiver<Envelope>,
+    shutdown_rx: NotifyReceiver<()>,
+    waker: WakerSlot,
+    capacity: Option<Arc<Capacity>>,
+}
+
+#[cfg(feature = "async")]
+impl Stream for CommandStream {
+    type Item = Command;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Command>> {
+        let this = Pin::into_inner(self);
+        loop {
+            // Bypass the `Recv` abstraction here and inspect `recv_timeout`'s
+            // `Result` directly: `Recv::poll()` folds "nothing yet" and
+            // "disconnected" into the same `None`, but this stream needs to
+            // tell them apart to end on disconnection instead of stalling.
+            match this.shutdown_rx.recv_timeout(Duration::from_millis(0)) {
+                Ok(Some(())) | Err(_) => return Poll::Ready(None),
+                Ok(None) => {},
+            }
+            match this.command_rx.recv_timeout(Duration::from_millis(0)) {
+                Ok(Some(Envelope::Cmd(cmd))) => {
+                    // Mirrors `Worker::run()`'s `Envelope::Cmd` arm: free the
+                    // slot this command was occupying so a producer parked
+                    // in `Capacity::acquire()`/`poll_acquire()` can proceed.
+                    if let Some(ref capacity) = this.capacity {
+                        capacity.release();
+                    }
+                    return Poll::Ready(Some(cmd));
+                },
+                Ok(Some(Envelope::Read(_, reply))) => drop(reply),
+                Ok(Some(Envelope::List(reply))) => drop(reply),
+                Ok(Some(Envelope::Watch(_, reply))) => drop(reply),
+                Ok(None) => {
+                    *this.waker.lock().unwrap() = Some(cx.waker().clone());
+                    return Poll::Pending;
+                },
+                Err(_) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// An async-aware shutdown handle paired with a `CommandStream`: like
+/// `Worker::shutdown_sender()`, but also wakes the stream's parked task
+/// so it notices immediately rather than only on the next `Command`.
+#[cfg(feature = "async")]
+pub struct AsyncShutdown {
+    sender: NotifySender<()>,
+    waker: WakerSlot,
+}
+
+#[cfg(feature = "async")]
+impl AsyncShutdown {
+    pub fn send(&self) -> Result<(), NotifyError> {
+        self.sender.send(())?;
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+/// Split a `Worker` into its async `Sink`/`Stream` halves, bypassing the
+/// blocking `Worker::run()` loop entirely.
+#[cfg(feature = "async")]
+pub fn into_async(worker: Worker) -> (AsyncCommands, CommandStream, AsyncShutdown) {
+    let waker: WakerSlot = Arc::new(Mutex::new(None));
+    let sender = AsyncCommands {
+        sender: worker.command_tx,
+        waker: waker.clone(),
+        capacity: worker.capacity.clone(),
+    };
+    let shutdown = AsyncShutdown {
+        sender: worker.shutdown_tx,
+        waker: waker.clone(),
+    };
+    let stream = CommandStream {
+        command_rx: worker.command_rx,
+        shutdown_rx: worker.shutdown_rx,
+        waker: waker,
+        capacity: worker.capacity.clone(),
+    };
+    (sender, stream, shutdown)
+}
+
 pub fn spawn_worker<H: Handle>() -> WorkerStub {
-    let worker = Worker::new();
+    spawn::<H>(Worker::new())
+}
+
+/// Spawn a worker whose command queue is bounded to `capacity` in-flight
+/// `Cmd` envelopes, so a handler that falls behind applies backpressure to
+/// producers instead of letting the queue grow without bound.
+pub fn spawn_bounded_worker<H: Handle>(capacity: usize) -> WorkerStub {
+    spawn::<H>(Worker::bounded(capacity))
+}
+
+/// Spawn a worker with an explicit `WorkerConfig`, e.g. to tune the
+/// timing wheel's tick rate away from the default.
+pub fn spawn_worker_with_config<H: Handle>(config: WorkerConfig) -> WorkerStub {
+    spawn::<H>(Worker::with_config(config))
+}
+
+fn spawn<H: Handle>(worker: Worker) -> WorkerStub {
     let sender = worker.sender();
+    let shutdown_sender = worker.shutdown_sender();
+    let capacity = worker.capacity.clone();
     let child = thread::spawn(move || {
         let mut handler = H::new();
         let mut worker = worker;
@@ -110,6 +801,8 @@ pub fn spawn_worker<H: Handle>() -> WorkerStub {
     });
     WorkerStub {
         sender: sender,
+        shutdown_sender: shutdown_sender,
+        capacity: capacity,
         child: child,
     }
 }
@@ -118,6 +811,7 @@ pub fn spawn_worker<H: Handle>() -> WorkerStub {
 mod tests {
     use std::sync::mpsc;
     use std::thread;
+    use std::time::Duration;
 
     use domain::{Command, Var, Value};
 
@@ -126,35 +820,67 @@ mod tests {
     #[test]
     fn should_shutdown() {
         let mut worker = Worker::new();
-        let tx = worker.sender();
-        let (polling_tx, _) = mpsc::channel();
+        let shutdown_tx = worker.shutdown_sender();
+        let (timeout_tx, _) = mpsc::channel();
         let (command_tx, _) = mpsc::channel();
         let child = thread::spawn(move || {
             let mut handler = MockHandle {
-                pollings: polling_tx,
+                timeouts: timeout_tx,
                 commands: command_tx,
             };
             worker.run(&mut handler);
         });
-        assert!(tx.send(Envelope::Shutdown).is_ok());
+        assert!(shutdown_tx.send(()).is_ok());
         assert!(child.join().is_ok());
     }
 
     #[test]
-    fn should_tick_polling() {
+    fn should_shutdown_ahead_of_pending_commands() {
         let mut worker = Worker::new();
         let tx = worker.sender();
-        let (polling_tx, polling_rx) = mpsc::channel();
-        let (command_tx, _) = mpsc::channel();
+        let shutdown_tx = worker.shutdown_sender();
+        let (timeout_tx, _) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
+
+        // Queue a backlog of commands and the shutdown signal before the
+        // worker thread even starts, so the very first iteration of its
+        // loop has to choose between them: the dedicated shutdown channel
+        // must win, leaving the backlog untouched.
+        for _ in 0..50 {
+            let cmd = Command::Write(Var::lvar("foobar"), Value::Bool(true));
+            assert!(tx.send(Envelope::Cmd(cmd)).is_ok());
+        }
+        assert!(shutdown_tx.send(()).is_ok());
+
+        let child = thread::spawn(move || {
+            let mut handler = MockHandle {
+                timeouts: timeout_tx,
+                commands: command_tx,
+            };
+            worker.run(&mut handler);
+        });
+        assert!(child.join().is_ok());
+        assert_eq!(command_rx.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn should_fire_scheduled_timeout() {
+        let mut worker = Worker::new();
+        let tx = worker.sender();
+        let shutdown_tx = worker.shutdown_sender();
+        let (timeout_tx, timeout_rx) = mpsc::channel();
+        let (command_tx, _command_rx) = mpsc::channel();
         let child = thread::spawn(move || {
             let mut handler = MockHandle {
-                pollings: polling_tx,
+                timeouts: timeout_tx,
                 commands: command_tx,
             };
             worker.run(&mut handler);
         });
-        assert!(polling_rx.recv().is_ok());
-        assert!(tx.send(Envelope::Shutdown).is_ok());
+        let cmd = Command::Write(Var::lvar("foobar"), Value::Bool(true));
+        assert!(tx.send(Envelope::Cmd(cmd)).is_ok());
+        assert_eq!(timeout_rx.recv().unwrap(), 42);
+        assert!(shutdown_tx.send(()).is_ok());
         assert!(child.join().is_ok());
     }
 
@@ -162,11 +888,12 @@ mod tests {
     fn should_process_msg() {
         let mut worker = Worker::new();
         let tx = worker.sender();
-        let (polling_tx, _polling_rx) = mpsc::channel();
+        let shutdown_tx = worker.shutdown_sender();
+        let (timeout_tx, _timeout_rx) = mpsc::channel();
         let (command_tx, command_rx) = mpsc::channel();
         let child = thread::spawn(move || {
             let mut handler = MockHandle {
-                pollings: polling_tx,
+                timeouts: timeout_tx,
                 commands: command_tx,
             };
             worker.run(&mut handler);
@@ -174,23 +901,332 @@ mod tests {
         let cmd = Command::Write(Var::lvar("foobar"), Value::Bool(true));
         assert!(tx.send(Envelope::Cmd(cmd.clone())).is_ok());
         assert_eq!(command_rx.recv().unwrap(), cmd);
-        assert!(tx.send(Envelope::Shutdown).is_ok());
+        assert!(shutdown_tx.send(()).is_ok());
         assert!(child.join().is_ok());
     }
 
     struct MockHandle {
-        pollings: mpsc::Sender<()>,
+        timeouts: mpsc::Sender<Token>,
         commands: mpsc::Sender<Command>,
     }
 
     impl Handle for MockHandle {
         fn new() -> MockHandle { unimplemented!() }
-        fn command(&mut self, cmd: Command) {
+
+        fn command(&mut self, cmd: Command, scheduler: &mut Scheduler, watchers: &mut Watchers) {
+            if let Command::Write(ref var, ref value) = cmd {
+                watchers.publish(var.clone(), value.clone());
+            }
             self.commands.send(cmd).unwrap();
+            scheduler.schedule(42, Duration::from_millis(5), false);
+        }
+
+        fn on_timeout(&mut self, token: Token, _scheduler: &mut Scheduler, _watchers: &mut Watchers) {
+            self.timeouts.send(token).unwrap();
+        }
+
+        fn read(&mut self, _var: Var) -> Value {
+            Value::Bool(true)
+        }
+
+        fn list(&mut self) -> Vec<Var> {
+            vec![Var::lvar("foobar")]
+        }
+    }
+
+    #[test]
+    fn should_answer_read_request() {
+        let mut worker = Worker::new();
+        let tx = worker.sender();
+        let shutdown_tx = worker.shutdown_sender();
+        let (timeout_tx, _) = mpsc::channel();
+        let (command_tx, _) = mpsc::channel();
+        let child = thread::spawn(move || {
+            let mut handler = MockHandle {
+                timeouts: timeout_tx,
+                commands: command_tx,
+            };
+            worker.run(&mut handler);
+        });
+        let (reply_tx, reply_rx) = ::util::oneshot();
+        assert!(tx.send(Envelope::Read(Var::lvar("foobar"), reply_tx)).is_ok());
+        assert_eq!(reply_rx.recv(), Ok(Value::Bool(true)));
+        assert!(shutdown_tx.send(()).is_ok());
+        assert!(child.join().is_ok());
+    }
+
+    #[test]
+    fn should_answer_list_request() {
+        let mut worker = Worker::new();
+        let mut consumer = worker.consumer();
+        let shutdown_tx = worker.shutdown_sender();
+        let (timeout_tx, _) = mpsc::channel();
+        let (command_tx, _) = mpsc::channel();
+        let child = thread::spawn(move || {
+            let mut handler = MockHandle {
+                timeouts: timeout_tx,
+                commands: command_tx,
+            };
+            worker.run(&mut handler);
+        });
+        let reply_rx = consumer.list().unwrap();
+        assert_eq!(reply_rx.recv(), Ok(vec![Var::lvar("foobar")]));
+        assert!(shutdown_tx.send(()).is_ok());
+        assert!(child.join().is_ok());
+    }
+
+    #[test]
+    fn should_subscribe_to_watch_channel_through_consumer() {
+        let mut worker = Worker::new();
+        let mut consumer = worker.consumer();
+        let tx = worker.sender();
+        let shutdown_tx = worker.shutdown_sender();
+        let (timeout_tx, _) = mpsc::channel();
+        let (command_tx, _command_rx) = mpsc::channel();
+        let child = thread::spawn(move || {
+            let mut handler = MockHandle {
+                timeouts: timeout_tx,
+                commands: command_tx,
+            };
+            worker.run(&mut handler);
+        });
+
+        let var = Var::lvar("foobar");
+        let mut rx = consumer.watch(var.clone())
+            .unwrap()
+            .recv()
+            .unwrap();
+        assert_eq!(rx.get(), (Value::Bool(true), 0));
+        assert!(!rx.has_changed());
+
+        let cmd = Command::Write(var, Value::Bool(false));
+        assert!(tx.send(Envelope::Cmd(cmd)).is_ok());
+        while !rx.has_changed() {
+            thread::yield_now();
+        }
+        assert_eq!(rx.get(), (Value::Bool(false), 1));
+
+        assert!(shutdown_tx.send(()).is_ok());
+        assert!(child.join().is_ok());
+    }
+
+    #[test]
+    fn should_publish_to_watchers() {
+        let mut watchers = Watchers::new();
+        let var = Var::lvar("foobar");
+        let mut rx = watchers.watch(var.clone(), Value::Bool(false));
+        assert_eq!(rx.get(), (Value::Bool(false), 0));
+        assert!(!rx.has_changed());
+
+        watchers.publish(var.clone(), Value::Bool(true));
+        assert!(rx.has_changed());
+        assert_eq!(rx.get(), (Value::Bool(true), 1));
+        assert!(!rx.has_changed());
+
+        let mut late_rx = watchers.watch(var, Value::Bool(false));
+        assert_eq!(late_rx.get(), (Value::Bool(true), 1));
+    }
+
+    #[test]
+    fn should_reject_try_consume_when_full() {
+        let worker = Worker::bounded(1);
+        let mut consumer = worker.consumer();
+        let cmd = Command::Write(Var::lvar("foobar"), Value::Bool(true));
+
+        assert!(consumer.try_consume(cmd.clone()).is_ok());
+        match consumer.try_consume(cmd) {
+            Err(ConsumeError::Full) => {},
+            _ => panic!("expected ConsumeError::Full"),
         }
+    }
+
+    #[test]
+    fn should_free_capacity_as_worker_drains_commands() {
+        let mut worker = Worker::bounded(1);
+        let mut consumer = worker.consumer();
+        let (timeout_tx, _) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
+        let shutdown_tx = worker.shutdown_sender();
+        let child = thread::spawn(move || {
+            let mut handler = MockHandle {
+                timeouts: timeout_tx,
+                commands: command_tx,
+            };
+            worker.run(&mut handler);
+        });
+
+        let cmd = Command::Write(Var::lvar("foobar"), Value::Bool(true));
+        assert!(consumer.try_consume(cmd.clone()).is_ok());
+        assert!(consumer.try_consume(cmd.clone()).is_err());
+        assert_eq!(command_rx.recv().unwrap(), cmd.clone());
+        assert!(consumer.try_consume(cmd).is_ok());
+
+        assert!(shutdown_tx.send(()).is_ok());
+        assert!(child.join().is_ok());
+    }
+
+    #[test]
+    fn should_block_consume_until_worker_frees_a_slot() {
+        let mut worker = Worker::bounded(1);
+        let mut consumer = worker.consumer();
+        let mut blocked_consumer = consumer.clone();
+        let (timeout_tx, _) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
+        let shutdown_tx = worker.shutdown_sender();
+        let child = thread::spawn(move || {
+            let mut handler = MockHandle {
+                timeouts: timeout_tx,
+                commands: command_tx,
+            };
+            worker.run(&mut handler);
+        });
+
+        let cmd = Command::Write(Var::lvar("foobar"), Value::Bool(true));
+        assert!(consumer.consume(cmd.clone()).is_ok());
+
+        let (unblocked_tx, unblocked_rx) = mpsc::channel();
+        let producer = thread::spawn(move || {
+            // With the single slot already occupied, this blocks until
+            // the worker drains the first command below.
+            blocked_consumer.consume(cmd).unwrap();
+            unblocked_tx.send(()).unwrap();
+        });
+
+        assert!(unblocked_rx.try_recv().is_err());
+        assert_eq!(command_rx.recv().unwrap(), Command::Write(Var::lvar("foobar"), Value::Bool(true)));
+        assert!(unblocked_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(producer.join().is_ok());
+
+        assert!(shutdown_tx.send(()).is_ok());
+        assert!(child.join().is_ok());
+    }
+
+    #[test]
+    fn should_unblock_consume_on_shutdown_instead_of_deadlocking() {
+        let mut worker = Worker::bounded(1);
+        let mut consumer = worker.consumer();
+        let mut blocked_consumer = consumer.clone();
+        let shutdown_tx = worker.shutdown_sender();
+        let (timeout_tx, _) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
 
-        fn poll(&mut self) {
-            self.pollings.send(()).unwrap();
+        // Occupy the single slot before the worker thread even starts, so
+        // the producer below blocks in `Capacity::acquire()` rather than
+        // racing the worker to it.
+        let cmd = Command::Write(Var::lvar("foobar"), Value::Bool(true));
+        assert!(consumer.consume(cmd.clone()).is_ok());
+
+        let (blocked_tx, blocked_rx) = mpsc::channel();
+        let producer = thread::spawn(move || {
+            blocked_tx.send(blocked_consumer.consume(cmd)).unwrap();
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(blocked_rx.try_recv().is_err());
+
+        // Queue the shutdown signal ahead of the worker thread starting:
+        // as in `should_shutdown_ahead_of_pending_commands`, the dedicated
+        // shutdown channel wins over the backlog, so the occupied slot is
+        // never drained. The blocked producer must be woken and failed
+        // instead of parked on the condvar forever.
+        assert!(shutdown_tx.send(()).is_ok());
+        let child = thread::spawn(move || {
+            let mut handler = MockHandle {
+                timeouts: timeout_tx,
+                commands: command_tx,
+            };
+            worker.run(&mut handler);
+        });
+
+        match blocked_rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Err(_)) => {},
+            other => panic!("expected the blocked producer to fail, got {:?}", other.map(|r| r.is_ok())),
         }
+        assert!(producer.join().is_ok());
+        assert!(child.join().is_ok());
+        assert_eq!(command_rx.try_iter().count(), 0);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn should_stream_commands_sent_through_the_async_sink() {
+        use futures::executor::block_on;
+        use futures::sink::SinkExt;
+        use futures::stream::StreamExt;
+
+        let worker = Worker::new();
+        let (mut sink, mut stream, shutdown) = into_async(worker);
+
+        let cmd = Command::Write(Var::lvar("foobar"), Value::Bool(true));
+        assert!(block_on(sink.send(cmd.clone())).is_ok());
+        assert_eq!(block_on(stream.next()), Some(cmd));
+
+        assert!(shutdown.send().is_ok());
+        assert_eq!(block_on(stream.next()), None);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn should_wake_stream_when_shutdown_is_requested_with_nothing_pending() {
+        use futures::executor::block_on;
+        use futures::stream::StreamExt;
+
+        let worker = Worker::new();
+        let (_sink, mut stream, shutdown) = into_async(worker);
+
+        // Nothing is pending, so the stream parks on its waker; this must
+        // resolve to None once `shutdown` fires, rather than hanging
+        // forever the way it did before it woke the stored waker too.
+        let shutdown_thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            assert!(shutdown.send().is_ok());
+        });
+
+        assert_eq!(block_on(stream.next()), None);
+        assert!(shutdown_thread.join().is_ok());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn should_apply_backpressure_to_the_async_sink() {
+        use futures::executor::block_on;
+        use futures::sink::SinkExt;
+        use futures::stream::StreamExt;
+
+        let worker = Worker::bounded(1);
+        let (mut sink, mut stream, _shutdown) = into_async(worker);
+
+        let cmd = Command::Write(Var::lvar("foobar"), Value::Bool(true));
+        assert!(block_on(sink.send(cmd.clone())).is_ok());
+
+        let (unblocked_tx, unblocked_rx) = mpsc::channel();
+        let second = cmd.clone();
+        let producer = thread::spawn(move || {
+            // The single slot is already occupied, so `poll_ready` parks
+            // this task until the stream below pulls the first command.
+            block_on(sink.send(second)).unwrap();
+            unblocked_tx.send(()).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(unblocked_rx.try_recv().is_err());
+
+        assert_eq!(block_on(stream.next()), Some(cmd.clone()));
+        assert!(unblocked_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(producer.join().is_ok());
+
+        assert_eq!(block_on(stream.next()), Some(cmd));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn should_end_stream_when_every_sink_has_dropped() {
+        use futures::executor::block_on;
+        use futures::stream::StreamExt;
+
+        let worker = Worker::new();
+        let (sink, mut stream, _shutdown) = into_async(worker);
+        drop(sink);
+
+        assert_eq!(block_on(stream.next()), None);
     }
 }
@@ -6,8 +6,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::fmt;
 use std::io;
 use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 pub trait Consume {
     type Item;
@@ -15,6 +17,21 @@ pub trait Consume {
     fn consume(&mut self, value: Self::Item) -> Result<(), Self::Error>;
 }
 
+/// Non-blocking counterpart to `Consume`, for producers that would rather
+/// shed load than block when the consumer is saturated.
+pub trait TryConsume: Consume {
+    fn try_consume(&mut self, value: Self::Item) -> Result<(), ConsumeError<Self::Error>>;
+}
+
+/// Mirrors the poll-ready/`is_full` split of an async sink: `Full` means
+/// try again later, `Disconnected` carries the underlying error because
+/// the consumer is gone for good.
+#[derive(Debug, PartialEq)]
+pub enum ConsumeError<E> {
+    Full,
+    Disconnected(E),
+}
+
 impl<T> Consume for mpsc::Sender<T> {
     type Item = T;
     type Error = io::Error;
@@ -25,3 +42,168 @@ impl<T> Consume for mpsc::Sender<T> {
         })
     }
 }
+
+/// The error a `OneshotReceiver` reports when its `OneshotSender` was
+/// dropped without ever sending a reply.
+#[derive(Debug, PartialEq)]
+pub enum RequestError {
+    Canceled,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RequestError::Canceled => write!(f, "request canceled before a reply was sent"),
+        }
+    }
+}
+
+/// The sending half of a one-shot reply channel. Meant to be used exactly
+/// once, to fulfil a single request with its single response.
+pub struct OneshotSender<T> {
+    tx: mpsc::Sender<T>,
+}
+
+impl<T> OneshotSender<T> {
+    /// Fulfil the request. The receiver may already have stopped waiting,
+    /// in which case the reply is silently dropped.
+    pub fn send(self, value: T) {
+        let _ = self.tx.send(value);
+    }
+}
+
+/// The receiving half of a one-shot reply channel.
+pub struct OneshotReceiver<T> {
+    rx: mpsc::Receiver<T>,
+}
+
+impl<T> OneshotReceiver<T> {
+    /// Block until the reply arrives. Fails with `RequestError::Canceled`
+    /// if the `OneshotSender` was dropped without sending one.
+    pub fn recv(self) -> Result<T, RequestError> {
+        self.rx.recv().map_err(|_| RequestError::Canceled)
+    }
+}
+
+/// Create a one-shot reply channel: a `(reply_tx, reply_rx)` pair meant to
+/// be threaded through a single request, one leg kept by the caller and
+/// the other moved into whatever will eventually produce the response.
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let (tx, rx) = mpsc::channel();
+    (OneshotSender { tx: tx }, OneshotReceiver { rx: rx })
+}
+
+/// Something that can be waited on for a pending item, blocking up to
+/// `timeout`. Implemented for the notification receivers so `select3()`
+/// can wait on several of them at once.
+pub trait Recv {
+    type Item;
+    fn poll(&mut self, timeout: Duration) -> Option<Self::Item>;
+}
+
+/// The arm of a `select3()` call that was ready first.
+pub enum Selected<A, B, C> {
+    First(A),
+    Second(B),
+    Third(C),
+}
+
+/// Time left before `deadline`, or `None` once it has passed.
+fn time_left(deadline: Instant) -> Option<Duration> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() { None } else { Some(remaining) }
+}
+
+/// Wait on three pollable sources at once, favouring earlier arguments
+/// when more than one is ready, and give up after `timeout` with `None`.
+///
+/// This is a hand-rolled stand-in for a real `select!`: `std::sync::mpsc`
+/// has no cross-channel wait, so this round-robins a real blocking
+/// `poll(slice)` over each source, shrinking the slice as the overall
+/// deadline approaches, instead of spin-polling with `yield_now()`. A
+/// caller blocked here parks the thread rather than burning a core.
+pub fn select3<R1, R2, R3>(
+    first: &mut R1,
+    second: &mut R2,
+    third: &mut R3,
+    timeout: Duration,
+) -> Option<Selected<R1::Item, R2::Item, R3::Item>>
+    where R1: Recv, R2: Recv, R3: Recv
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = time_left(deadline)?;
+        if let Some(item) = first.poll(remaining / 3) {
+            return Some(Selected::First(item));
+        }
+
+        let remaining = time_left(deadline)?;
+        if let Some(item) = second.poll(remaining / 2) {
+            return Some(Selected::Second(item));
+        }
+
+        let remaining = time_left(deadline)?;
+        if let Some(item) = third.poll(remaining) {
+            return Some(Selected::Third(item));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_deliver_reply() {
+        let (tx, rx) = oneshot();
+        tx.send(42);
+        assert_eq!(rx.recv(), Ok(42));
+    }
+
+    #[test]
+    fn should_cancel_when_sender_dropped() {
+        let (tx, rx) = oneshot::<i32>();
+        drop(tx);
+        assert_eq!(rx.recv(), Err(RequestError::Canceled));
+    }
+
+    struct ChannelRecv<T> {
+        rx: mpsc::Receiver<T>,
+    }
+
+    impl<T> Recv for ChannelRecv<T> {
+        type Item = T;
+        fn poll(&mut self, timeout: Duration) -> Option<T> {
+            self.rx.recv_timeout(timeout).ok()
+        }
+    }
+
+    #[test]
+    fn should_select_first_ready_source() {
+        let (tx1, rx1) = mpsc::channel();
+        let (_tx2, rx2) = mpsc::channel::<()>();
+        let (_tx3, rx3) = mpsc::channel::<()>();
+        let mut first = ChannelRecv { rx: rx1 };
+        let mut second = ChannelRecv { rx: rx2 };
+        let mut third = ChannelRecv { rx: rx3 };
+
+        tx1.send("ready").unwrap();
+        match select3(&mut first, &mut second, &mut third, Duration::from_millis(50)) {
+            Some(Selected::First(v)) => assert_eq!(v, "ready"),
+            _ => panic!("expected the first source to win"),
+        }
+    }
+
+    #[test]
+    fn should_time_out_when_nothing_is_ready() {
+        let (_tx1, rx1) = mpsc::channel::<()>();
+        let (_tx2, rx2) = mpsc::channel::<()>();
+        let (_tx3, rx3) = mpsc::channel::<()>();
+        let mut first = ChannelRecv { rx: rx1 };
+        let mut second = ChannelRecv { rx: rx2 };
+        let mut third = ChannelRecv { rx: rx3 };
+
+        let selected = select3(&mut first, &mut second, &mut third, Duration::from_millis(10));
+        assert!(selected.is_none());
+    }
+}